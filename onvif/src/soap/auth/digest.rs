@@ -1,45 +1,103 @@
 use crate::soap::client::Credentials;
-use reqwest::{RequestBuilder, Response};
+use crate::soap::middleware::{Error, Middleware, Next};
+use async_trait::async_trait;
+use base64::Engine;
+use reqwest::{RequestBuilder, Response, StatusCode};
 use std::fmt::{Debug, Formatter};
-use thiserror::Error;
 use url::Url;
 
-#[derive(Debug, Error)]
-pub enum Error {
-    #[error("Invalid state")]
-    InvalidState,
-    #[error("No credentials")]
-    NoCredentials,
-    #[error("Digest {0}")]
-    Digest(String),
+/// Which `WWW-Authenticate` scheme to use when a device offers more than
+/// one. Defaults to preferring `Digest` when both are on offer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthScheme {
+    Basic,
+    Digest,
 }
 
 pub struct Digest {
     creds: Option<Credentials>,
     uri: Url,
     state: State,
+    /// Force a single scheme instead of auto-negotiating from the challenge.
+    preferred_scheme: Option<AuthScheme>,
+    /// Algorithms we're willing to answer with, strongest first. The first
+    /// entry present in the server's challenge wins.
+    algorithm_preference: Vec<digest_auth::Algorithm>,
 }
 
 enum State {
     Default,
     Got401(reqwest::Response),
     Got401Twice,
+    /// A digest challenge we've already satisfied once. Kept around so later
+    /// requests can attach an `Authorization` header up front instead of
+    /// always eating a 401 round-trip first. Each `respond()` call bumps the
+    /// header's own nonce-count and draws a fresh client nonce, so reusing it
+    /// is safe as long as the server hasn't told us the nonce went stale.
+    Cached(digest_auth::WwwAuthenticateHeader),
+    /// Basic was negotiated: the device offered only Basic, or the caller
+    /// forced Basic and the device offered it. There's no server-held nonce
+    /// to track, so every request can just carry the header preemptively.
+    Basic,
 }
 
 impl Digest {
     pub fn new(uri: &Url, creds: &Option<Credentials>) -> Self {
+        Self::with_preferences(uri, creds, None, default_algorithm_preference())
+    }
+
+    /// Like [`Digest::new`], but forces `preferred_scheme` instead of
+    /// auto-negotiating from the `WWW-Authenticate` challenge. Fails if the
+    /// device doesn't actually offer the requested scheme.
+    pub fn with_scheme_preference(
+        uri: &Url,
+        creds: &Option<Credentials>,
+        preferred_scheme: Option<AuthScheme>,
+    ) -> Self {
+        Self::with_preferences(uri, creds, preferred_scheme, default_algorithm_preference())
+    }
+
+    /// Full control over both the scheme and the digest algorithm order.
+    /// `algorithm_preference` is tried strongest-first against whatever the
+    /// device's challenge actually offers; a device that only offers an
+    /// algorithm absent from the list fails with
+    /// [`Error::UnsupportedAlgorithm`].
+    pub fn with_preferences(
+        uri: &Url,
+        creds: &Option<Credentials>,
+        preferred_scheme: Option<AuthScheme>,
+        algorithm_preference: Vec<digest_auth::Algorithm>,
+    ) -> Self {
         Self {
             creds: creds.clone(),
             uri: uri.clone(),
             state: State::Default,
+            preferred_scheme,
+            algorithm_preference,
         }
     }
 }
 
+/// RFC 7616 lists MD5, MD5-sess, SHA-256, SHA-256-sess, SHA-512-256 and
+/// SHA-512-256-sess. We default to the strongest available, falling back to
+/// MD5 since that's still the most commonly deployed on ONVIF devices.
+fn default_algorithm_preference() -> Vec<digest_auth::Algorithm> {
+    use digest_auth::{Algorithm, AlgorithmType};
+
+    vec![
+        Algorithm { algo: AlgorithmType::SHA2_512_256, sess: true },
+        Algorithm { algo: AlgorithmType::SHA2_512_256, sess: false },
+        Algorithm { algo: AlgorithmType::SHA2_256, sess: true },
+        Algorithm { algo: AlgorithmType::SHA2_256, sess: false },
+        Algorithm { algo: AlgorithmType::MD5, sess: true },
+        Algorithm { algo: AlgorithmType::MD5, sess: false },
+    ]
+}
+
 impl Digest {
     pub fn set_401(&mut self, response: Response) {
         match self.state {
-            State::Default => self.state = State::Got401(response),
+            State::Default | State::Cached(_) | State::Basic => self.state = State::Got401(response),
             State::Got401(_) => self.state = State::Got401Twice,
             State::Got401Twice => {}
         }
@@ -49,57 +107,237 @@ impl Digest {
         matches!(self.state, State::Got401Twice)
     }
 
-    pub fn add_headers(&self, mut request: RequestBuilder) -> Result<RequestBuilder, Error> {
-        match &self.state {
+    /// Whether the current state lets us attach `Authorization` before
+    /// sending, rather than waiting for a 401 to learn the scheme.
+    fn sends_preemptively(&self) -> bool {
+        matches!(self.state, State::Cached(_) | State::Basic)
+    }
+
+    pub fn add_headers(&mut self, mut request: RequestBuilder, body: &[u8]) -> Result<RequestBuilder, Error> {
+        let state = std::mem::replace(&mut self.state, State::Default);
+
+        match state {
             State::Default => Ok(request),
+            State::Basic => {
+                self.state = State::Basic;
+                let creds = self.creds.as_ref().ok_or(Error::NoCredentials)?;
+                Ok(request.header(reqwest::header::AUTHORIZATION, basic_auth_header(creds)))
+            }
             State::Got401(response) => {
                 let creds = self.creds.as_ref().ok_or(Error::NoCredentials)?;
 
-                request = request.header(
-                    reqwest::header::AUTHORIZATION,
-                    digest_auth(response, creds, &self.uri)?,
-                );
+                match negotiate_scheme(&response, self.preferred_scheme)? {
+                    AuthScheme::Basic => {
+                        self.state = State::Basic;
+                        Ok(request.header(reqwest::header::AUTHORIZATION, basic_auth_header(creds)))
+                    }
+                    AuthScheme::Digest => {
+                        let mut header = select_challenge(&response, &self.algorithm_preference)?;
+                        let context = auth_context(creds, &self.uri, body);
+                        let authorization = header
+                            .respond(&context)
+                            .map_err(|e| Error::Digest(e.to_string()))?
+                            .to_string();
 
+                        self.state = State::Cached(header);
+                        Ok(request.header(reqwest::header::AUTHORIZATION, authorization))
+                    }
+                }
+            }
+            State::Cached(mut header) => {
+                let creds = self.creds.as_ref().ok_or(Error::NoCredentials)?;
+                let context = auth_context(creds, &self.uri, body);
+                let authorization = header
+                    .respond(&context)
+                    .map_err(|e| Error::Digest(e.to_string()))?
+                    .to_string();
+
+                self.state = State::Cached(header);
+                request = request.header(reqwest::header::AUTHORIZATION, authorization);
                 Ok(request)
             }
-            State::Got401Twice => Err(Error::InvalidState),
+            State::Got401Twice => {
+                self.state = State::Got401Twice;
+                Err(Error::InvalidState)
+            }
         }
     }
 }
 
-fn digest_auth(res: &reqwest::Response, creds: &Credentials, url: &Url) -> Result<String, Error> {
-    let www_auth_headers = res.headers().get_all(reqwest::header::WWW_AUTHENTICATE);
+#[async_trait]
+impl Middleware for Digest {
+    /// Sends the request with a preemptive `Authorization` header whenever
+    /// the scheme is already known (cached Digest challenge, or a device
+    /// that only offers Basic), falling back to an unauthenticated first
+    /// attempt otherwise. A 401 on the unauthenticated first exchange, or on
+    /// an authenticated request whose nonce just went stale, is treated as a
+    /// fresh challenge to answer and retried. A 401 on a request that already
+    /// carried credentials and wasn't stale is a genuine credential failure.
+    async fn handle(&mut self, request: RequestBuilder, mut next: Next<'_>) -> Result<Response, Error> {
+        if self.is_failed() {
+            return Err(Error::InvalidState);
+        }
+
+        let retry = clone_for_retry(&request)?;
+        let body = request_body(&retry)?;
 
-    let mut www_authenticate = None;
+        let mut authenticated = self.sends_preemptively();
+        let first = if authenticated {
+            self.add_headers(request, &body)?
+        } else {
+            request
+        };
 
-    for method in ["algorithm=sha", "algorithm=md5", "digest"] {
-        for www_auth in www_auth_headers.iter() {
-            let header_str = www_auth
-                .to_str()
-                .map_err(|e| Error::Digest(e.to_string()))?;
-            if header_str.to_ascii_lowercase().contains(method) {
-                www_authenticate = Some(header_str);
-                break;
+        let mut response = next.run(first).await?;
+
+        // Budget a handful of round-trips: the initial probe, the ordinary
+        // authenticated retry, and a couple of stale-nonce recoveries in a
+        // row in case a device keeps rotating nonces on us.
+        for _ in 0..3 {
+            if response.status() != StatusCode::UNAUTHORIZED {
+                return Ok(response);
+            }
+
+            let stale = authenticated && is_stale_challenge(&response, &self.algorithm_preference);
+            if authenticated && !stale {
+                self.state = State::Got401Twice;
+                return Err(Error::InvalidState);
             }
+
+            self.set_401(response);
+            let authed = self.add_headers(clone_for_retry(&retry)?, &body)?;
+            response = next.run(authed).await?;
+            authenticated = true;
         }
-        if www_authenticate.is_some() {
-            break;
+
+        if response.status() == StatusCode::UNAUTHORIZED {
+            self.state = State::Got401Twice;
+            return Err(Error::InvalidState);
         }
+
+        Ok(response)
     }
+}
+
+fn clone_for_retry(request: &RequestBuilder) -> Result<RequestBuilder, Error> {
+    request
+        .try_clone()
+        .ok_or_else(|| Error::Digest("request body cannot be cloned for a digest retry".to_string()))
+}
 
-    let www_authenticate = www_authenticate.ok_or(Error::Digest(
-        "No www-authenticate digest header".to_string(),
-    ))?;
+fn is_stale_challenge(res: &reqwest::Response, algorithm_preference: &[digest_auth::Algorithm]) -> bool {
+    select_challenge(res, algorithm_preference)
+        .map(|header| header.stale)
+        .unwrap_or(false)
+}
+
+/// Extracts the serialized request body so it can be hashed into the digest
+/// response when a server requires `qop="auth-int"`. SOAP requests always
+/// carry a body, so this clones the builder rather than consuming the
+/// original, which is still needed to send the request.
+fn request_body(request: &RequestBuilder) -> Result<Vec<u8>, Error> {
+    let request = clone_for_retry(request)?.build()?;
 
-    let mut context = digest_auth::AuthContext::new(&creds.username, &creds.password, url.path());
+    Ok(request
+        .body()
+        .and_then(|body| body.as_bytes())
+        .map(|bytes| bytes.to_vec())
+        .unwrap_or_default())
+}
 
-    context.method = digest_auth::HttpMethod::POST;
+fn auth_context<'a>(creds: &'a Credentials, url: &'a Url, body: &'a [u8]) -> digest_auth::AuthContext<'a> {
+    // Always supply the body so that a server offering `qop="auth-int"` gets
+    // a correct H(entity-body) term; `respond()` picks the strongest of the
+    // qop values the challenge offers.
+    digest_auth::AuthContext::new_with_method(
+        &creds.username,
+        &creds.password,
+        url.path(),
+        Some(body),
+        digest_auth::HttpMethod::POST,
+    )
+}
 
-    Ok(digest_auth::parse(www_authenticate)
-        .map_err(|e| Error::Digest(e.to_string()))?
-        .respond(&context)
-        .map_err(|e| Error::Digest(e.to_string()))?
-        .to_string())
+fn basic_auth_header(creds: &Credentials) -> String {
+    let encoded = base64::engine::general_purpose::STANDARD
+        .encode(format!("{}:{}", creds.username, creds.password));
+    format!("Basic {encoded}")
+}
+
+/// Inspects every `WWW-Authenticate` header on a 401 and decides which
+/// scheme to answer with: the caller's forced preference if the device
+/// actually offers it, otherwise Digest over Basic when both are present.
+fn negotiate_scheme(res: &reqwest::Response, preferred: Option<AuthScheme>) -> Result<AuthScheme, Error> {
+    let challenges: Vec<String> = res
+        .headers()
+        .get_all(reqwest::header::WWW_AUTHENTICATE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(|value| value.to_ascii_lowercase())
+        .collect();
+
+    let has_digest = challenges.iter().any(|c| c.starts_with("digest"));
+    let has_basic = challenges.iter().any(|c| c.starts_with("basic"));
+
+    if let Some(scheme) = preferred {
+        return match scheme {
+            AuthScheme::Digest if has_digest => Ok(AuthScheme::Digest),
+            AuthScheme::Basic if has_basic => Ok(AuthScheme::Basic),
+            scheme => Err(Error::Digest(format!(
+                "server did not offer the requested {scheme:?} auth scheme"
+            ))),
+        };
+    }
+
+    if has_digest {
+        Ok(AuthScheme::Digest)
+    } else if has_basic {
+        Ok(AuthScheme::Basic)
+    } else {
+        Err(Error::Digest(
+            "No www-authenticate digest or basic header".to_string(),
+        ))
+    }
+}
+
+/// Parses every `WWW-Authenticate: Digest ...` challenge the device sent
+/// (RFC 7616 allows one per algorithm) and picks the first one whose
+/// algorithm appears in `algorithm_preference`, strongest entry first.
+fn select_challenge(
+    res: &reqwest::Response,
+    algorithm_preference: &[digest_auth::Algorithm],
+) -> Result<digest_auth::WwwAuthenticateHeader, Error> {
+    let mut offered = Vec::new();
+    for www_auth in res.headers().get_all(reqwest::header::WWW_AUTHENTICATE).iter() {
+        let header_str = www_auth
+            .to_str()
+            .map_err(|e| Error::Digest(e.to_string()))?;
+        if !header_str.trim_start().to_ascii_lowercase().starts_with("digest") {
+            continue;
+        }
+        if let Ok(header) = digest_auth::parse(header_str) {
+            offered.push(header);
+        }
+    }
+
+    if offered.is_empty() {
+        return Err(Error::Digest(
+            "No www-authenticate digest header".to_string(),
+        ));
+    }
+
+    for algorithm in algorithm_preference {
+        if let Some(index) = offered.iter().position(|header| &header.algorithm == algorithm) {
+            return Ok(offered.swap_remove(index));
+        }
+    }
+
+    let offered_algorithms = offered
+        .iter()
+        .map(|header| format!("{:?}", header.algorithm))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Err(Error::UnsupportedAlgorithm(offered_algorithms))
 }
 
 impl Debug for Digest {
@@ -107,6 +345,7 @@ impl Debug for Digest {
         f.debug_struct("Digest")
             .field("creds", &self.creds)
             .field("state", &self.state)
+            .field("preferred_scheme", &self.preferred_scheme)
             .finish()
     }
 }
@@ -117,6 +356,245 @@ impl Debug for State {
             State::Default => "FirstRequest",
             State::Got401(_) => "Got401",
             State::Got401Twice => "Got401Twice",
+            State::Cached(_) => "Cached",
+            State::Basic => "Basic",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::soap::middleware::Next;
+
+    fn creds() -> Option<Credentials> {
+        Some(Credentials {
+            username: "admin".to_string(),
+            password: "admin".to_string(),
         })
     }
+
+    async fn send(digest: &mut Digest, client: &reqwest::Client, url: &Url, body: &'static str) -> Response {
+        let mut middlewares: Vec<Box<dyn Middleware>> = vec![];
+        let request = client.post(url.clone()).body(body);
+        let next = Next::new(client, &mut middlewares);
+        digest.handle(request, next).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn auth_int_hashes_the_request_body() {
+        let mut server = mockito::Server::new_async().await;
+        let www_authenticate = "Digest realm=\"onvif\", nonce=\"abc123\", qop=\"auth-int\"";
+
+        let unauthorized = server
+            .mock("POST", "/onvif/device_service")
+            .with_status(401)
+            .with_header("www-authenticate", www_authenticate)
+            .expect(1)
+            .create_async()
+            .await;
+        let authorized = server
+            .mock("POST", "/onvif/device_service")
+            .match_header("authorization", mockito::Matcher::Regex("qop=auth-int".to_string()))
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let url = Url::parse(&format!("{}/onvif/device_service", server.url())).unwrap();
+        let client = reqwest::Client::new();
+        let mut digest = Digest::new(&url, &creds());
+
+        let response = send(&mut digest, &client, &url, "<soap:Envelope/>").await;
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        unauthorized.assert_async().await;
+        authorized.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn reuses_the_cached_challenge_without_a_second_401() {
+        let mut server = mockito::Server::new_async().await;
+        let www_authenticate = "Digest realm=\"onvif\", nonce=\"abc123\", qop=\"auth\"";
+
+        server
+            .mock("POST", "/onvif/device_service")
+            .match_header("authorization", mockito::Matcher::Missing)
+            .with_status(401)
+            .with_header("www-authenticate", www_authenticate)
+            .expect(1)
+            .create_async()
+            .await;
+        let first_authed = server
+            .mock("POST", "/onvif/device_service")
+            .match_header("authorization", mockito::Matcher::Regex("nc=00000001".to_string()))
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+        let second_authed = server
+            .mock("POST", "/onvif/device_service")
+            .match_header("authorization", mockito::Matcher::Regex("nc=00000002".to_string()))
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let url = Url::parse(&format!("{}/onvif/device_service", server.url())).unwrap();
+        let client = reqwest::Client::new();
+        let mut digest = Digest::new(&url, &creds());
+
+        send(&mut digest, &client, &url, "<soap:Envelope/>").await;
+        assert!(matches!(digest.state, State::Cached(_)));
+        first_authed.assert_async().await;
+
+        let response = send(&mut digest, &client, &url, "<soap:Envelope/>").await;
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        second_authed.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn adopts_a_fresh_nonce_on_stale_and_retries_once() {
+        let mut server = mockito::Server::new_async().await;
+        let first_challenge = "Digest realm=\"onvif\", nonce=\"abc123\", qop=\"auth\"";
+        let stale_challenge = "Digest realm=\"onvif\", nonce=\"def456\", qop=\"auth\", stale=true";
+
+        server
+            .mock("POST", "/onvif/device_service")
+            .match_header("authorization", mockito::Matcher::Missing)
+            .with_status(401)
+            .with_header("www-authenticate", first_challenge)
+            .expect(1)
+            .create_async()
+            .await;
+        let stale = server
+            .mock("POST", "/onvif/device_service")
+            .match_header("authorization", mockito::Matcher::Regex("nonce=\"abc123\"".to_string()))
+            .with_status(401)
+            .with_header("www-authenticate", stale_challenge)
+            .expect(1)
+            .create_async()
+            .await;
+        let retried = server
+            .mock("POST", "/onvif/device_service")
+            .match_header("authorization", mockito::Matcher::Regex("nonce=\"def456\"".to_string()))
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let url = Url::parse(&format!("{}/onvif/device_service", server.url())).unwrap();
+        let client = reqwest::Client::new();
+        let mut digest = Digest::new(&url, &creds());
+
+        // A single call negotiates abc123, hits the stale 401 on its first
+        // authenticated attempt, and recovers by adopting def456 — all
+        // within the one middleware invocation.
+        let response = send(&mut digest, &client, &url, "<soap:Envelope/>").await;
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert!(matches!(digest.state, State::Cached(_)));
+        stale.assert_async().await;
+        retried.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_basic_when_digest_is_not_offered() {
+        let mut server = mockito::Server::new_async().await;
+
+        server
+            .mock("POST", "/onvif/device_service")
+            .with_status(401)
+            .with_header("www-authenticate", "Basic realm=\"onvif\"")
+            .expect(1)
+            .create_async()
+            .await;
+        let authorized = server
+            .mock("POST", "/onvif/device_service")
+            .match_header("authorization", "Basic YWRtaW46YWRtaW4=")
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let url = Url::parse(&format!("{}/onvif/device_service", server.url())).unwrap();
+        let client = reqwest::Client::new();
+        let mut digest = Digest::new(&url, &creds());
+
+        let response = send(&mut digest, &client, &url, "<soap:Envelope/>").await;
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert!(matches!(digest.state, State::Basic));
+        authorized.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn prefers_digest_when_both_schemes_are_offered() {
+        let mut server = mockito::Server::new_async().await;
+
+        server
+            .mock("POST", "/onvif/device_service")
+            .with_status(401)
+            .with_header("www-authenticate", "Basic realm=\"onvif\"")
+            .with_header(
+                "www-authenticate",
+                "Digest realm=\"onvif\", nonce=\"abc123\", qop=\"auth\"",
+            )
+            .expect(1)
+            .create_async()
+            .await;
+        let authorized = server
+            .mock("POST", "/onvif/device_service")
+            .match_header("authorization", mockito::Matcher::Regex("Digest ".to_string()))
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let url = Url::parse(&format!("{}/onvif/device_service", server.url())).unwrap();
+        let client = reqwest::Client::new();
+        let mut digest = Digest::new(&url, &creds());
+
+        let response = send(&mut digest, &client, &url, "<soap:Envelope/>").await;
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert!(matches!(digest.state, State::Cached(_)));
+        authorized.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn errors_when_the_device_only_offers_an_unwanted_algorithm() {
+        let mut server = mockito::Server::new_async().await;
+
+        server
+            .mock("POST", "/onvif/device_service")
+            .with_status(401)
+            .with_header(
+                "www-authenticate",
+                "Digest realm=\"onvif\", nonce=\"abc123\", qop=\"auth\", algorithm=SHA-512-256",
+            )
+            .expect(1)
+            .create_async()
+            .await;
+
+        let url = Url::parse(&format!("{}/onvif/device_service", server.url())).unwrap();
+        let client = reqwest::Client::new();
+        let mut digest = Digest::with_preferences(
+            &url,
+            &creds(),
+            None,
+            vec![digest_auth::Algorithm {
+                algo: digest_auth::AlgorithmType::MD5,
+                sess: false,
+            }],
+        );
+
+        let mut middlewares: Vec<Box<dyn Middleware>> = vec![];
+        let request = client.post(url.clone()).body("<soap:Envelope/>");
+        let next = Next::new(&client, &mut middlewares);
+        let err = digest.handle(request, next).await.unwrap_err();
+
+        assert!(matches!(err, Error::UnsupportedAlgorithm(_)));
+    }
 }
@@ -0,0 +1,56 @@
+use async_trait::async_trait;
+use reqwest::{RequestBuilder, Response};
+use thiserror::Error;
+
+/// Errors that can occur while building or running a request through the
+/// middleware chain.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid state")]
+    InvalidState,
+    #[error("No credentials")]
+    NoCredentials,
+    #[error("Digest {0}")]
+    Digest(String),
+    #[error("Unsupported digest algorithm(s): {0}")]
+    UnsupportedAlgorithm(String),
+    #[error(transparent)]
+    Reqwest(#[from] reqwest::Error),
+}
+
+/// A single layer in the SOAP client's request pipeline.
+///
+/// A middleware can inspect or rewrite the outgoing request, inspect the
+/// response coming back, or retry by calling [`Next::run`] more than once.
+/// Call `next.run(request)` to hand the request to the rest of the chain
+/// (or to the underlying `reqwest::Client` if this is the last middleware).
+/// This is what `Digest` auth is built on, and is the extension point for
+/// things like logging, retry/backoff, or custom headers.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    async fn handle(&mut self, request: RequestBuilder, next: Next<'_>) -> Result<Response, Error>;
+}
+
+/// The remainder of the middleware chain still to run.
+pub struct Next<'a> {
+    client: &'a reqwest::Client,
+    middlewares: &'a mut [Box<dyn Middleware>],
+}
+
+impl<'a> Next<'a> {
+    pub fn new(client: &'a reqwest::Client, middlewares: &'a mut [Box<dyn Middleware>]) -> Self {
+        Self { client, middlewares }
+    }
+
+    /// Run `request` through the rest of the chain, executing it against the
+    /// underlying client once no middlewares remain.
+    pub async fn run(&mut self, request: RequestBuilder) -> Result<Response, Error> {
+        match self.middlewares {
+            [] => {
+                let request = request.build()?;
+                Ok(self.client.execute(request).await?)
+            }
+            [head, tail @ ..] => head.handle(request, Next::new(self.client, tail)).await,
+        }
+    }
+}
@@ -0,0 +1,3 @@
+pub mod auth;
+pub mod client;
+pub mod middleware;